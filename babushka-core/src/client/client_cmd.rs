@@ -5,6 +5,7 @@ use logger_core::log_trace;
 use redis::aio::{ConnectionLike, MultiplexedConnection};
 use redis::{RedisError, RedisResult};
 use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
@@ -16,6 +17,12 @@ use super::{
     DEFAULT_RESPONSE_TIMEOUT,
 };
 
+/// How often the heartbeat task checks on the health of a pooled connection.
+const HEARTBEAT_SLEEP_DURATION: Duration = Duration::from_secs(1);
+
+/// Number of connections in the pool when `ConnectionRequest::connections_count` isn't set.
+const DEFAULT_CONNECTIONS_COUNT: usize = 1;
+
 /// The object that is used in order to recreate a connection after a disconnect.
 struct ConnectionBackend {
     /// This signal is reset when a connection disconnects, and set when a new `ConnectionState` has been set with either a `Connected` or a `Disconnected` state.
@@ -23,6 +30,9 @@ struct ConnectionBackend {
     connection_available_signal: ManualResetEvent,
     /// Information needed in order to create a new connection.
     connection_info: redis::Client,
+    /// Set by `DropWrapper::drop` once the last user-facing clone of the `ClientCMD` is gone, so
+    /// that this slot's heartbeat task knows to stop looping instead of keeping the client alive.
+    client_dropped: AtomicBool,
 }
 
 /// State of the current connection. Allows the user to use a connection only when a reconnect isn't in progress or has failed.
@@ -38,34 +48,144 @@ enum ConnectionState {
 /// This allows us to safely share and replace the connection state between clones of the client.
 type ConnectionWrapper = Arc<Mutex<ConnectionState>>;
 
+/// Thin wrapper around one pooled slot's connection state whose only purpose is to notice when
+/// the last user-facing clone of a `ClientCMD` goes away; see `client_dropped`.
+struct DropWrapper {
+    primary: ConnectionWrapper,
+}
+
+impl Drop for DropWrapper {
+    fn drop(&mut self) {
+        // `Drop` can't be async, so best-effort grab the lock; if it's held, whoever holds it
+        // will observe this `DropWrapper` going away through the `Weak` upgrade failing instead.
+        if let Ok(guard) = self.primary.try_lock() {
+            match &*guard {
+                ConnectionState::Connected(_, backend) | ConnectionState::Reconnecting(backend) => {
+                    backend.client_dropped.store(true, Ordering::Relaxed);
+                }
+                ConnectionState::Disconnected => {}
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ClientCMD {
-    /// Connection to the primary node in the client.
-    primary: ConnectionWrapper,
+    /// Pool of connections to the primary node. Spreading commands across several multiplexed
+    /// connections avoids a single pipeline becoming a head-of-line bottleneck under high
+    /// concurrency; a disconnect of one slot doesn't stall commands dispatched to healthy slots.
+    primary: Arc<Vec<Arc<DropWrapper>>>,
+    /// Round-robin cursor into `primary`, shared between clones of this client.
+    next_slot: Arc<AtomicUsize>,
     connection_retry_strategy: RetryStrategy,
     response_timeout: Duration,
 }
 
+/// Creates a new multiplexed connection and writes it back into `slot` as soon as it's live,
+/// rather than leaving that to the caller, so concurrent waiters in `get_connection` immediately
+/// reuse the recovered connection instead of racing to open their own.
 async fn try_create_multiplexed_connection(
+    slot: ConnectionWrapper,
     connection_backend: Arc<ConnectionBackend>,
     retry_strategy: RetryStrategy,
 ) -> RedisResult<MultiplexedConnection> {
     let client = &connection_backend.connection_info;
     let action = || client.get_multiplexed_async_connection();
 
-    Retry::spawn(retry_strategy.get_iterator(), action).await
+    let connection = Retry::spawn(retry_strategy.get_iterator(), action).await?;
+    {
+        let mut guard = slot.lock().await;
+        *guard = ConnectionState::Connected(connection.clone(), connection_backend.clone());
+    }
+    connection_backend.connection_available_signal.set();
+    Ok(connection)
 }
 
-async fn try_create_connection(
-    connection_backend: Arc<ConnectionBackend>,
+fn get_disconnected_error<T>() -> Result<T, RedisError> {
+    let io_error: io::Error = io::ErrorKind::BrokenPipe.into();
+    Err(io_error.into())
+}
+
+async fn get_connection_for_slot(slot: &ConnectionWrapper) -> Result<MultiplexedConnection, RedisError> {
+    loop {
+        // Using a limited scope in order to release the mutex lock before waiting for notifications.
+        let backend = {
+            let mut guard = slot.lock().await;
+            match &mut *guard {
+                ConnectionState::Reconnecting(backend) => backend.clone(),
+                ConnectionState::Connected(connection, _) => {
+                    return Ok(connection.clone());
+                }
+                ConnectionState::Disconnected => {
+                    return get_disconnected_error();
+                }
+            }
+        };
+        backend.connection_available_signal.wait().await;
+    }
+}
+
+/// Reconnects a single pooled slot. Takes just the slot and retry strategy (not a whole
+/// `ClientCMD`) so that callers who only have a `Weak` handle on the slot - namely the heartbeat
+/// task, which must not hold the pool alive - can trigger a reconnect without reconstructing one.
+async fn reconnect_slot(
+    slot: ConnectionWrapper,
     retry_strategy: RetryStrategy,
-) -> RedisResult<ConnectionWrapper> {
-    let connection =
-        try_create_multiplexed_connection(connection_backend.clone(), retry_strategy).await?;
-    Ok(Arc::new(Mutex::new(ConnectionState::Connected(
-        connection,
-        connection_backend,
-    ))))
+) -> Result<MultiplexedConnection, RedisError> {
+    let backend = {
+        let mut guard = slot.lock().await;
+        let backend = match &*guard {
+            ConnectionState::Connected(_, backend) => {
+                backend.connection_available_signal.reset();
+                backend.clone()
+            }
+            _ => {
+                // exit early - if reconnection already started or failed, there's nothing else to do.
+                // The lock is released first, since `get_connection_for_slot` takes it again.
+                drop(guard);
+                return get_connection_for_slot(&slot).await;
+            }
+        };
+        *guard = ConnectionState::Reconnecting(backend.clone());
+        backend
+    };
+    let connection_wrapper = slot.clone();
+    // The reconnect task is spawned instead of awaited here, so that if this task will be dropped for some reason, the reconnection attempt will continue.
+    task::spawn(async move {
+        // On success, `try_create_multiplexed_connection` already writes the recovered
+        // connection back into `connection_wrapper` and sets the signal; only the failure
+        // path needs to be handled here.
+        let connection_result =
+            try_create_multiplexed_connection(connection_wrapper.clone(), backend.clone(), retry_strategy)
+                .await;
+        if let Ok(connection) = connection_result {
+            Ok(connection)
+        } else {
+            let mut guard = connection_wrapper.lock().await;
+            *guard = ConnectionState::Disconnected;
+            backend.connection_available_signal.set();
+            get_disconnected_error()
+        }
+    });
+    get_connection_for_slot(&slot).await
+}
+
+async fn create_slot(
+    address: &AddressInfo,
+    tls_mode: TlsMode,
+    redis_connection_info: redis::RedisConnectionInfo,
+    retry_strategy: RetryStrategy,
+) -> RedisResult<Arc<DropWrapper>> {
+    let backend = Arc::new(ConnectionBackend {
+        connection_info: get_client(address, tls_mode, redis_connection_info)?,
+        connection_available_signal: ManualResetEvent::new(false),
+        client_dropped: AtomicBool::new(false),
+    });
+    let primary: ConnectionWrapper = Arc::new(Mutex::new(ConnectionState::Reconnecting(
+        backend.clone(),
+    )));
+    try_create_multiplexed_connection(primary.clone(), backend, retry_strategy).await?;
+    Ok(Arc::new(DropWrapper { primary }))
 }
 
 fn get_client(
@@ -93,91 +213,115 @@ impl ClientCMD {
         );
 
         let retry_strategy = RetryStrategy::new(&connection_request.connection_retry_strategy.0);
-        let redis_connection_info =
-            get_redis_connection_info(connection_request.authentication_info.0);
-        let client = Arc::new(ConnectionBackend {
-            connection_info: get_client(
-                address,
-                connection_request.tls_mode.enum_value_or(TlsMode::NoTls),
-                redis_connection_info,
-            )?,
-            connection_available_signal: ManualResetEvent::new(true),
-        });
-        let primary = try_create_connection(client, retry_strategy.clone()).await?;
+        let tls_mode = connection_request.tls_mode.enum_value_or(TlsMode::NoTls);
+        // `<= 0` (rather than `== 0`) also guards against a negative value wrapping into a huge
+        // `usize` below, regardless of whether the field is a signed or unsigned protobuf type.
+        let connections_count = if connection_request.connections_count <= 0 {
+            DEFAULT_CONNECTIONS_COUNT
+        } else {
+            connection_request.connections_count as usize
+        };
+
+        let mut primary = Vec::with_capacity(connections_count);
+        for _ in 0..connections_count {
+            let redis_connection_info =
+                get_redis_connection_info(connection_request.authentication_info.0.clone());
+            primary.push(
+                create_slot(
+                    address,
+                    tls_mode,
+                    redis_connection_info,
+                    retry_strategy.clone(),
+                )
+                .await?,
+            );
+        }
         log_trace(
             "client creation",
             format!("Connection to {address} created"),
         );
-        Ok(Self {
-            primary,
+        let client = Self {
+            primary: Arc::new(primary),
+            next_slot: Arc::new(AtomicUsize::new(0)),
             connection_retry_strategy: retry_strategy,
             response_timeout,
-        })
+        };
+        client.start_heartbeat();
+        Ok(client)
     }
 
-    fn get_disconnected_error<T>() -> Result<T, RedisError> {
-        let io_error: io::Error = io::ErrorKind::BrokenPipe.into();
-        Err(io_error.into())
-    }
+    /// Spawns, for each slot in the pool, a task that periodically pings its connection so that a
+    /// silently dropped connection is discovered even if no command happens to be sent on it.
+    /// Holds only a `Weak` reference to the slot (see `DropWrapper`), and exits once
+    /// `client_dropped` is set.
+    fn start_heartbeat(&self) {
+        for slot in self.primary.iter() {
+            let weak_primary = Arc::downgrade(slot);
+            let connection_retry_strategy = self.connection_retry_strategy.clone();
+            let response_timeout = self.response_timeout;
+            task::spawn(async move {
+                loop {
+                    tokio::time::sleep(HEARTBEAT_SLEEP_DURATION).await;
+                    let Some(slot) = weak_primary.upgrade() else {
+                        break;
+                    };
 
-    async fn get_connection(&self) -> Result<MultiplexedConnection, RedisError> {
-        loop {
-            // Using a limited scope in order to release the mutex lock before waiting for notifications.
-            let backend = {
-                let mut guard = self.primary.lock().await;
-                match &mut *guard {
-                    ConnectionState::Reconnecting(backend) => backend.clone(),
-                    ConnectionState::Connected(connection, _) => {
-                        return Ok(connection.clone());
+                    let connection_and_backend = {
+                        let guard = slot.primary.lock().await;
+                        match &*guard {
+                            ConnectionState::Connected(connection, backend) => {
+                                Some((connection.clone(), backend.clone()))
+                            }
+                            _ => None,
+                        }
+                    };
+
+                    let Some((mut connection, backend)) = connection_and_backend else {
+                        continue;
+                    };
+                    if backend.client_dropped.load(Ordering::Relaxed) {
+                        break;
                     }
-                    ConnectionState::Disconnected => {
-                        return Self::get_disconnected_error();
+
+                    // Bounded the same way as a regular command: on a half-open connection, the
+                    // PING would otherwise never resolve, and the heartbeat would never loop
+                    // again to notice the disconnect.
+                    let result = run_with_timeout(
+                        response_timeout,
+                        connection.send_packed_command(&redis::cmd("PING")),
+                    )
+                    .await;
+                    if let Err(err) = result {
+                        if err.is_connection_dropped() || err.is_timeout() {
+                            let _ =
+                                reconnect_slot(slot.primary.clone(), connection_retry_strategy.clone())
+                                    .await;
+                        }
                     }
                 }
-            };
-            backend.connection_available_signal.wait().await;
+            });
         }
     }
 
-    async fn reconnect(&self) -> Result<MultiplexedConnection, RedisError> {
-        let backend = {
-            let mut guard = self.primary.lock().await;
-            let backend = match &*guard {
-                ConnectionState::Connected(_, backend) => {
-                    backend.connection_available_signal.reset();
-                    backend.clone()
-                }
-                _ => {
-                    // exit early - if reconnection already started or failed, there's nothing else to do.
-                    return self.get_connection().await;
-                }
-            };
-            *guard = ConnectionState::Reconnecting(backend.clone());
-            backend
-        };
-        let clone = self.clone();
-        // The reconnect task is spawned instead of awaited here, so that if this task will be dropped for some reason, the reconnection attempt will continue.
-        task::spawn(async move {
-            let connection_result = try_create_multiplexed_connection(
-                backend.clone(),
-                clone.connection_retry_strategy.clone(),
-            )
-            .await;
-            let mut guard = clone.primary.lock().await;
-            backend.connection_available_signal.set();
-            if let Ok(connection) = connection_result {
-                *guard = ConnectionState::Connected(connection.clone(), backend.clone());
-                Ok(connection)
-            } else {
-                *guard = ConnectionState::Disconnected;
-                Self::get_disconnected_error()
-            }
-        });
-        self.get_connection().await
+    /// Picks the next slot to dispatch a command to, in round-robin order.
+    fn next_slot(&self) -> usize {
+        self.next_slot.fetch_add(1, Ordering::Relaxed) % self.primary.len()
+    }
+
+    async fn get_connection(&self, slot: usize) -> Result<MultiplexedConnection, RedisError> {
+        get_connection_for_slot(&self.primary[slot].primary).await
+    }
+
+    async fn reconnect(&self, slot: usize) -> Result<MultiplexedConnection, RedisError> {
+        reconnect_slot(
+            self.primary[slot].primary.clone(),
+            self.connection_retry_strategy.clone(),
+        )
+        .await
     }
 
     async fn send_command(
-        &mut self,
+        &self,
         cmd: &redis::Cmd,
         mut connection: MultiplexedConnection,
     ) -> redis::RedisResult<redis::Value> {
@@ -185,23 +329,28 @@ impl ClientCMD {
     }
 
     pub async fn send_packed_command(
-        &mut self,
+        &self,
         cmd: &redis::Cmd,
     ) -> redis::RedisResult<redis::Value> {
-        let connection = self.get_connection().await?;
+        let slot = self.next_slot();
+        let connection = self.get_connection(slot).await?;
         let result = self.send_command(cmd, connection).await;
-        match result {
-            Ok(val) => Ok(val),
-            Err(err) if err.is_connection_dropped() => {
-                let connection = self.reconnect().await?;
-                self.send_command(cmd, connection).await
+        if let Err(err) = &result {
+            if err.is_connection_dropped() {
+                // `MultiplexedConnection` fails every in-flight request the instant its
+                // background I/O task sees a socket error, whether or not that request had
+                // already reached the server - there's no way to tell from here whether `cmd`
+                // was already executed, so it must never be silently resent. The slot is
+                // reconnected instead (waiting for that attempt to finish) and the original
+                // error is returned.
+                let _ = self.reconnect(slot).await;
             }
-            Err(err) => Err(err),
         }
+        result
     }
 
     async fn send_commands(
-        &mut self,
+        &self,
         cmd: &redis::Pipeline,
         offset: usize,
         count: usize,
@@ -215,27 +364,66 @@ impl ClientCMD {
     }
 
     pub(super) async fn send_packed_commands(
-        &mut self,
+        &self,
         cmd: &redis::Pipeline,
         offset: usize,
         count: usize,
     ) -> redis::RedisResult<Vec<redis::Value>> {
-        let connection = self.get_connection().await?;
+        let slot = self.next_slot();
+        let connection = self.get_connection(slot).await?;
         let result = self.send_commands(cmd, offset, count, connection).await;
-        match result {
-            Ok(val) => Ok(val),
-            Err(err) if err.is_connection_dropped() => {
-                let connection = self.reconnect().await?;
-                self.send_commands(cmd, offset, count, connection).await
+        if let Err(err) = &result {
+            if err.is_connection_dropped() {
+                // See the matching comment in `send_packed_command`: a dropped-connection error
+                // here doesn't prove the pipeline wasn't already executed, so it's surfaced
+                // as-is rather than resent.
+                let _ = self.reconnect(slot).await;
             }
-            Err(err) => Err(err),
         }
+        result
     }
     pub(super) fn get_db(&self) -> i64 {
-        let guard = self.primary.blocking_lock();
+        let guard = self.primary[0].primary.blocking_lock();
         match &*guard {
             ConnectionState::Connected(connection, _) => connection.get_db(),
             _ => -1,
         }
     }
 }
+
+#[cfg(test)]
+mod next_slot_tests {
+    use super::*;
+
+    fn client_with_slots(count: usize) -> ClientCMD {
+        let primary = (0..count)
+            .map(|_| {
+                Arc::new(DropWrapper {
+                    primary: Arc::new(Mutex::new(ConnectionState::Disconnected)),
+                })
+            })
+            .collect();
+        ClientCMD {
+            primary: Arc::new(primary),
+            next_slot: Arc::new(AtomicUsize::new(0)),
+            connection_retry_strategy: RetryStrategy::new(&Default::default()),
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+
+    #[test]
+    fn next_slot_round_robins_across_the_pool() {
+        let client = client_with_slots(3);
+        let picked: Vec<_> = (0..7).map(|_| client.next_slot()).collect();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn next_slot_cursor_is_shared_across_clones() {
+        let client = client_with_slots(2);
+        let clone = client.clone();
+        assert_eq!(client.next_slot(), 0);
+        assert_eq!(clone.next_slot(), 1);
+        assert_eq!(client.next_slot(), 0);
+    }
+}